@@ -1389,6 +1389,56 @@ pub fn modify_vtl_protection_mask(
         Ok(())
     }
 
+    /// Checks whether the target vtl has vtl permissions for the given gpa.
+    ///
+    /// [`HypercallCode::HvCallCheckSparseGpaPageVtlAccess`] must be allowed.
+    pub fn check_vtl_access(
+        &self,
+        gpa: u64,
+        target_vtl: HvInputVtl,
+        flags: HvMapGpaFlags,
+    ) -> Result<Option<CheckVtlAccessResult>, Error> {
+        let header = hvdef::hypercall::CheckSparseGpaPageVtlAccess {
+            partition_id: HV_PARTITION_ID_SELF,
+            target_vtl,
+            desired_access: u32::from(flags) as u8,
+            reserved0: 0,
+            reserved1: 0,
+        };
+
+        let mut output = [hvdef::hypercall::CheckSparseGpaPageVtlAccessOutput::new()];
+
+        // SAFETY: The input header and rep slice are the correct types for this hypercall.
+        //         The hypercall output is validated right after the hypercall is issued.
+        let status = unsafe {
+            self.hvcall_rep::<hvdef::hypercall::CheckSparseGpaPageVtlAccess, u64, hvdef::hypercall::CheckSparseGpaPageVtlAccessOutput>(
+                HypercallCode::HvCallCheckSparseGpaPageVtlAccess,
+                &header,
+                HvcallRepInput::Elements(&[gpa >> hvdef::HV_PAGE_SHIFT]),
+                Some(&mut output),
+            )
+            .expect("check_vtl_access hypercall should not fail")
+        };
+
+        status.result().map_err(Error::CheckVtlAccess)?;
+
+        let access_result = output[0];
+
+        if access_result.result_code() as u32
+            != hvdef::hypercall::CheckGpaPageVtlAccessResultCode::SUCCESS.0
+        {
+            return Ok(Some(CheckVtlAccessResult {
+                vtl: (access_result.intercepting_vtl() as u8)
+                    .try_into()
+                    .expect("checking vtl permissions failure should return valid vtl"),
+                denied_flags: (access_result.denied_access() as u32).into(),
+            }));
+        }
+
+        assert_eq!(status.elements_processed(), 1);
+        Ok(None)
+    }
+
     /// Get a single VP register for the given VTL via hypercall.
     fn get_vp_register_for_vtl_inner(
         &self,
@@ -1768,6 +1818,10 @@ pub enum NoRunner {
     /// A sidecar VP was requested, but no sidecar was provided.
     #[error("missing sidecar")]
     MissingSidecar,
+    /// A sidecar VP was provided, but the isolation type does not support
+    /// sidecar.
+    #[error("sidecar not supported for this isolation type")]
+    SidecarNotSupported,
     /// The sidecar VP could not be contacted.
     #[error("sidecar communication error")]
     Sidecar(#[source] sidecar_client::SidecarError),
@@ -3097,45 +3151,8 @@ pub fn check_vtl_access(
     ) -> Result<Option<CheckVtlAccessResult>, Error> {
         assert!(!self.isolation.is_hardware_isolated());
 
-        let header = hvdef::hypercall::CheckSparseGpaPageVtlAccess {
-            partition_id: HV_PARTITION_ID_SELF,
-            target_vtl: HvInputVtl::from(target_vtl),
-            desired_access: u32::from(flags) as u8,
-            reserved0: 0,
-            reserved1: 0,
-        };
-
-        let mut output = [hvdef::hypercall::CheckSparseGpaPageVtlAccessOutput::new()];
-
-        // SAFETY: The input header and rep slice are the correct types for this hypercall.
-        //         The hypercall output is validated right after the hypercall is issued.
-        let status = unsafe {
-            self.mshv_hvcall.hvcall_rep::<hvdef::hypercall::CheckSparseGpaPageVtlAccess, u64, hvdef::hypercall::CheckSparseGpaPageVtlAccessOutput>(
-                HypercallCode::HvCallCheckSparseGpaPageVtlAccess,
-                &header,
-                HvcallRepInput::Elements(&[gpa >> hvdef::HV_PAGE_SHIFT]),
-                Some(&mut output),
-            )
-            .expect("check_vtl_access hypercall should not fail")
-        };
-
-        status.result().map_err(Error::CheckVtlAccess)?;
-
-        let access_result = output[0];
-
-        if access_result.result_code() as u32
-            != hvdef::hypercall::CheckGpaPageVtlAccessResultCode::SUCCESS.0
-        {
-            return Ok(Some(CheckVtlAccessResult {
-                vtl: (access_result.intercepting_vtl() as u8)
-                    .try_into()
-                    .expect("checking vtl permissions failure should return valid vtl"),
-                denied_flags: (access_result.denied_access() as u32).into(),
-            }));
-        }
-
-        assert_eq!(status.elements_processed(), 1);
-        Ok(None)
+        self.mshv_hvcall
+            .check_vtl_access(gpa, HvInputVtl::from(target_vtl), flags)
     }
 
     /// Enables a vtl for the partition
@@ -3270,42 +3287,12 @@ pub fn retarget_device_interrupt(
         status.result()
     }
 
-    /// Gets the permissions for a vtl.
+    /// Gets the permissions for a vtl, along with the size of the RMP entry
+    /// covering the page.
     /// Currently unused, but available for debugging purposes
     #[cfg(debug_assertions)]
-    pub fn rmp_query(&self, gpa: u64, vtl: GuestVtl) -> x86defs::snp::SevRmpAdjust {
-        use x86defs::snp::SevRmpAdjust;
-
-        let page_count = 1u64;
-        let flags = [u64::from(SevRmpAdjust::new().with_target_vmpl(match vtl {
-            GuestVtl::Vtl0 => 2,
-            GuestVtl::Vtl1 => 1,
-        }))];
-        let page_size = [0u64];
-        let pages_processed = 0;
-
-        debug_assert!(flags.len() == page_count as usize);
-        debug_assert!(page_size.len() == page_count as usize);
-
-        let query = mshv_rmpquery {
-            start_pfn: gpa / HV_PAGE_SIZE,
-            page_count,
-            terminate_on_failure: 0,
-            ram: 0,
-            padding: Default::default(),
-            flags: flags.as_ptr().cast_mut(),
-            page_size: page_size.as_ptr().cast_mut(),
-            pages_processed: core::ptr::from_ref(&pages_processed).cast_mut(),
-        };
-
-        // SAFETY: the input query is the correct type for this ioctl
-        unsafe {
-            hcl_rmpquery_pages(self.mshv_vtl.file.as_raw_fd(), &query)
-                .expect("should always succeed");
-        }
-        debug_assert!(pages_processed <= page_count);
-
-        SevRmpAdjust::from(flags[0])
+    pub fn rmp_query(&self, gpa: u64, vtl: GuestVtl) -> Result<snp::RmpQuery, snp::SnpPageError> {
+        self.mshv_vtl.rmpquery_page(gpa, vtl)
     }
 
     /// Issues an INVLPGB instruction.
@@ -41,6 +41,10 @@ pub enum SnpError {
     Os(#[source] nix::Error),
     #[error("isa error {0:?}")]
     Isa(u32),
+    #[error("unrecognized rmp flags {0:#x}")]
+    InvalidRmpFlags(u64),
+    #[error("vmsa conversion not supported by the kernel")]
+    VmsaConversionUnsupported(#[source] nix::Error),
 }
 
 /// Error returned by failing SNP page operations.
@@ -55,6 +59,25 @@ pub enum SnpPageError {
     Rmpquery(#[source] SnpError),
 }
 
+open_enum::open_enum! {
+    /// The size of the RMP entry covering a page.
+    pub enum RmpPageSize: u64 {
+        /// A 4KB RMP entry.
+        SIZE_4K = 0,
+        /// A 2MB RMP entry.
+        SIZE_2M = 1,
+    }
+}
+
+/// The result of querying the RMP entry of a page.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RmpQuery {
+    /// The VTL permissions of the page.
+    pub permissions: SevRmpAdjust,
+    /// The size of the RMP entry covering the page.
+    pub page_size: RmpPageSize,
+}
+
 impl MshvVtl {
     /// Execute the pvalidate instruction on the specified memory range.
     ///
@@ -106,10 +129,41 @@ pub fn rmpadjust_pages(
         terminate_on_failure: bool,
     ) -> Result<(), SnpPageError> {
         if value.vmsa() {
-            // TODO SNP: VMSA conversion does not work.
+            // TODO SNP: VMSA conversion does not work. Callers that need it
+            // must opt in via `rmpadjust_vmsa_pages`.
             return Ok(());
         }
 
+        self.rmpadjust_pages_raw(range, value, terminate_on_failure)
+            .map_err(SnpPageError::Rmpadjust)
+    }
+
+    /// Execute the rmpadjust instruction on the specified memory range,
+    /// converting the pages to VMSA pages.
+    ///
+    /// Unlike [`Self::rmpadjust_pages`], this actually issues the conversion
+    /// to the kernel, failing with [`SnpError::VmsaConversionUnsupported`] if
+    /// the kernel does not support it.
+    ///
+    /// The range must not be mapped in the kernel as RAM.
+    pub fn rmpadjust_vmsa_pages(
+        &self,
+        range: MemoryRange,
+        value: SevRmpAdjust,
+        terminate_on_failure: bool,
+    ) -> Result<(), SnpPageError> {
+        tracing::debug!(%range, terminate_on_failure, "rmpadjust vmsa");
+        self.rmpadjust_pages_raw(range, value.with_vmsa(true), terminate_on_failure)
+            .map_err(vmsa_rmpadjust_error)
+            .map_err(SnpPageError::Rmpadjust)
+    }
+
+    fn rmpadjust_pages_raw(
+        &self,
+        range: MemoryRange,
+        value: SevRmpAdjust,
+        terminate_on_failure: bool,
+    ) -> Result<(), SnpError> {
         #[expect(clippy::undocumented_unsafe_blocks)] // TODO SNP
         let ret = unsafe {
             hcl_rmpadjust_pages(
@@ -123,20 +177,20 @@ pub fn rmpadjust_pages(
                     padding: Default::default(),
                 },
             )
-            .map_err(SnpError::Os)
-            .map_err(SnpPageError::Rmpadjust)?
+            .map_err(SnpError::Os)?
         };
 
         if ret != 0 {
-            return Err(SnpPageError::Rmpadjust(SnpError::Isa(ret as u32)));
+            return Err(SnpError::Isa(ret as u32));
         }
 
         Ok(())
     }
 
-    /// Gets the current vtl permissions for a page.
+    /// Gets the current vtl permissions for a page, along with the size of the
+    /// RMP entry covering it.
     /// Note: only supported on Genoa+
-    pub fn rmpquery_page(&self, gpa: u64, vtl: GuestVtl) -> Result<SevRmpAdjust, SnpPageError> {
+    pub fn rmpquery_page(&self, gpa: u64, vtl: GuestVtl) -> Result<RmpQuery, SnpPageError> {
         let page_count = 1u64;
         let mut flags = [u64::from(SevRmpAdjust::new().with_target_vmpl(match vtl {
             GuestVtl::Vtl0 => 2,
@@ -169,13 +223,56 @@ pub fn rmpquery_page(&self, gpa: u64, vtl: GuestVtl) -> Result<SevRmpAdjust, Snp
 
         assert!(pages_processed <= page_count);
 
-        Ok(SevRmpAdjust::from(flags[0]))
+        parse_rmpquery(flags[0], page_size[0]).map_err(SnpPageError::Rmpquery)
     }
 }
 
+/// Validates the RMP permission flags returned by the kernel, rejecting values
+/// with reserved bits set or an out-of-range VMPL.
+fn parse_rmp_flags(flags: u64) -> Result<SevRmpAdjust, SnpError> {
+    let value = SevRmpAdjust::from(flags);
+    // Rebuild the value from the known fields, dropping any reserved bits.
+    let known = SevRmpAdjust::new()
+        .with_target_vmpl(value.target_vmpl())
+        .with_enable_read(value.enable_read())
+        .with_enable_write(value.enable_write())
+        .with_enable_user_execute(value.enable_user_execute())
+        .with_enable_kernel_execute(value.enable_kernel_execute())
+        .with_vmsa(value.vmsa());
+    if u64::from(known) != flags || value.target_vmpl() > 3 {
+        return Err(SnpError::InvalidRmpFlags(flags));
+    }
+    Ok(value)
+}
+
+/// Maps the OS error from a VMSA rmpadjust, distinguishing kernels that do not
+/// support VMSA conversion.
+///
+/// `EINVAL` is deliberately not mapped, since the kernel also returns it for
+/// invalid ranges or arguments.
+fn vmsa_rmpadjust_error(err: SnpError) -> SnpError {
+    match err {
+        SnpError::Os(err @ (nix::Error::EOPNOTSUPP | nix::Error::ENOTTY)) => {
+            SnpError::VmsaConversionUnsupported(err)
+        }
+        err => err,
+    }
+}
+
+/// Builds the result of an rmpquery from the raw flags and page size returned
+/// by the kernel.
+fn parse_rmpquery(flags: u64, page_size: u64) -> Result<RmpQuery, SnpError> {
+    Ok(RmpQuery {
+        permissions: parse_rmp_flags(flags)?,
+        page_size: RmpPageSize(page_size),
+    })
+}
+
 impl<'a> super::private::BackingPrivate<'a> for Snp<'a> {
     fn new(vp: &'a HclVp, sidecar: Option<&SidecarVp<'_>>, _hcl: &Hcl) -> Result<Self, NoRunner> {
-        assert!(sidecar.is_none());
+        if sidecar.is_some() {
+            return Err(NoRunner::SidecarNotSupported);
+        }
         let super::BackingState::Snp { vmsa } = &vp.backing else {
             return Err(NoRunner::MismatchedIsolation);
         };
@@ -243,3 +340,72 @@ pub fn vmsa_mut(&mut self, vtl: GuestVtl) -> VmsaWrapper<'_, &mut SevVmsa> {
             .into_inner()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rmp_flags() {
+        let value = SevRmpAdjust::new()
+            .with_target_vmpl(2)
+            .with_enable_read(true)
+            .with_enable_write(true);
+        assert_eq!(parse_rmp_flags(value.into()).unwrap(), value);
+
+        // Reserved bits.
+        assert!(matches!(
+            parse_rmp_flags(u64::from(value) | 0x1000),
+            Err(SnpError::InvalidRmpFlags(_))
+        ));
+        assert!(matches!(
+            parse_rmp_flags(!0),
+            Err(SnpError::InvalidRmpFlags(_))
+        ));
+
+        // Out of range VMPL.
+        assert!(matches!(
+            parse_rmp_flags(SevRmpAdjust::new().with_target_vmpl(4).into()),
+            Err(SnpError::InvalidRmpFlags(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_rmpquery_page_size() {
+        let value = SevRmpAdjust::new()
+            .with_target_vmpl(2)
+            .with_enable_read(true);
+
+        let query = parse_rmpquery(value.into(), 1).unwrap();
+        assert_eq!(query.permissions, value);
+        assert_eq!(query.page_size, RmpPageSize::SIZE_2M);
+
+        let query = parse_rmpquery(value.into(), 0).unwrap();
+        assert_eq!(query.page_size, RmpPageSize::SIZE_4K);
+    }
+
+    #[test]
+    fn test_vmsa_rmpadjust_error() {
+        assert!(matches!(
+            vmsa_rmpadjust_error(SnpError::Os(nix::Error::EOPNOTSUPP)),
+            SnpError::VmsaConversionUnsupported(nix::Error::EOPNOTSUPP)
+        ));
+        assert!(matches!(
+            vmsa_rmpadjust_error(SnpError::Os(nix::Error::ENOTTY)),
+            SnpError::VmsaConversionUnsupported(nix::Error::ENOTTY)
+        ));
+        // Invalid arguments are a caller bug, not a kernel limitation.
+        assert!(matches!(
+            vmsa_rmpadjust_error(SnpError::Os(nix::Error::EINVAL)),
+            SnpError::Os(nix::Error::EINVAL)
+        ));
+        assert!(matches!(
+            vmsa_rmpadjust_error(SnpError::Os(nix::Error::EFAULT)),
+            SnpError::Os(nix::Error::EFAULT)
+        ));
+        assert!(matches!(
+            vmsa_rmpadjust_error(SnpError::Isa(1)),
+            SnpError::Isa(1)
+        ));
+    }
+}
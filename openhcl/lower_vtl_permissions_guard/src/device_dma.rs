@@ -9,6 +9,7 @@
 // MemoryBlock.
 #![expect(unsafe_code)]
 
+use crate::LoweredPageReservation;
 use crate::PagesAccessibleToLowerVtl;
 use inspect::Inspect;
 use user_driver::memory::MappedDmaTarget;
@@ -18,9 +19,14 @@
 /// access to all VTLs.
 #[derive(Inspect)]
 pub struct LowerVtlDmaBuffer {
+    // Fields are dropped in declaration order: the guard restores the page
+    // protections, then the reservation is released, and only then is the
+    // memory returned to the inner allocator for reuse.
+    pub(crate) _vtl_guard: PagesAccessibleToLowerVtl,
+    #[inspect(skip)]
+    pub(crate) _reservation: LoweredPageReservation,
     #[inspect(skip)]
     pub(crate) block: MemoryBlock,
-    pub(crate) _vtl_guard: PagesAccessibleToLowerVtl,
 }
 
 // SAFETY: The underlying MemoryBlock is providing the implementation for this
@@ -13,13 +13,30 @@
 use anyhow::Context;
 use anyhow::Result;
 use inspect::Inspect;
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::ops::Range;
 use std::sync::Arc;
 use user_driver::DmaClient;
 use user_driver::memory::MemoryBlock;
 use virt::VtlMemoryProtection;
 
+/// Checks the lower VTL's access to pages, to verify that permission changes
+/// took effect.
+pub trait CheckVtlMemoryAccess {
+    /// Returns whether the lower VTL has `flags` access to a physical page.
+    fn check_access(&self, pfn: u64, flags: hvdef::HvMapGpaFlags) -> Result<bool>;
+}
+
 /// A guard that will restore [`hvdef::HV_MAP_GPA_PERMISSIONS_NONE`] permissions
 /// on the pages when dropped.
+///
+/// Permission changes are flushed via
+/// [`VtlMemoryProtection::flush_vtl_page_settings`] both after lowering and
+/// after restoring. The lowered permissions are therefore visible before the
+/// guard is returned, and the restored permissions are visible when the drop
+/// completes. [`LowerVtlDmaBuffer`] drops the guard before its memory block,
+/// so the memory is not freed until the restore is complete.
 #[derive(Inspect)]
 struct PagesAccessibleToLowerVtl {
     #[inspect(skip)]
@@ -35,15 +52,40 @@ fn new_from_pages(
         vtl_protect: Arc<dyn VtlMemoryProtection + Send + Sync>,
         pages: &[u64],
     ) -> Result<Self> {
+        // Track each page in the guard as soon as it is lowered, so that an
+        // early return drops the guard and restores the pages lowered so far.
+        let mut guard = Self {
+            vtl_protect,
+            pages: Vec::with_capacity(pages.len()),
+        };
         for pfn in pages {
-            vtl_protect
+            guard
+                .vtl_protect
                 .modify_vtl_page_setting(*pfn, hvdef::HV_MAP_GPA_PERMISSIONS_ALL)
                 .context("failed to update VTL protections on page")?;
+            guard.pages.push(*pfn);
         }
-        Ok(Self {
-            vtl_protect,
-            pages: pages.to_vec(),
-        })
+        guard
+            .vtl_protect
+            .flush_vtl_page_settings()
+            .context("failed to flush VTL protections")?;
+        Ok(guard)
+    }
+
+    /// Checks that the lower VTL has `flags` access to the guarded pages, as
+    /// reported by `check`.
+    fn verify_protections(
+        &self,
+        check: &dyn CheckVtlMemoryAccess,
+        flags: hvdef::HvMapGpaFlags,
+    ) -> Result<()> {
+        for pfn in &self.pages {
+            let allowed = check
+                .check_access(*pfn, flags)
+                .context("failed to check VTL access to page")?;
+            anyhow::ensure!(allowed, "page {pfn:#x} does not allow {flags:?} access");
+        }
+        Ok(())
     }
 }
 
@@ -58,6 +100,11 @@ fn drop(&mut self) {
                     .context("failed to update VTL protections on page")
             })
             .collect::<Result<Vec<_>>>()
+            .and_then(|_| {
+                self.vtl_protect
+                    .flush_vtl_page_settings()
+                    .context("failed to flush VTL protections")
+            })
         {
             // The inability to rollback any pages is fatal. We cannot leave the
             // pages in the state where the correct VTL protections are not
@@ -71,6 +118,46 @@ fn drop(&mut self) {
     }
 }
 
+/// A reservation of pages with lowered VTL permissions, released when
+/// dropped.
+///
+/// Used to detect overlapping allocations, whose guards would otherwise
+/// restore protections on pages still in use by another allocation.
+struct LoweredPageReservation {
+    live_pages: Arc<Mutex<HashSet<u64>>>,
+    pages: Vec<u64>,
+}
+
+impl LoweredPageReservation {
+    /// Reserves `pages`, failing if any of them are already reserved.
+    fn new(live_pages: Arc<Mutex<HashSet<u64>>>, pages: &[u64]) -> Result<Self> {
+        {
+            let mut live = live_pages.lock();
+            for (i, pfn) in pages.iter().enumerate() {
+                if !live.insert(*pfn) {
+                    for pfn in &pages[..i] {
+                        live.remove(pfn);
+                    }
+                    anyhow::bail!("page {pfn:#x} is already accessible to the lower VTL");
+                }
+            }
+        }
+        Ok(Self {
+            live_pages,
+            pages: pages.to_vec(),
+        })
+    }
+}
+
+impl Drop for LoweredPageReservation {
+    fn drop(&mut self) {
+        let mut live = self.live_pages.lock();
+        for pfn in &self.pages {
+            live.remove(pfn);
+        }
+    }
+}
+
 /// A [`DmaClient`] wrapper that will lower the VTL permissions of the page
 /// on the allocated memory block.
 #[derive(Inspect)]
@@ -79,6 +166,10 @@ pub struct LowerVtlMemorySpawner<T: DmaClient> {
     spawner: T,
     #[inspect(skip)]
     vtl_protect: Arc<dyn VtlMemoryProtection + Send + Sync>,
+    #[inspect(skip)]
+    live_pages: Arc<Mutex<HashSet<u64>>>,
+    #[inspect(skip)]
+    verifier: Option<Arc<dyn CheckVtlMemoryAccess + Send + Sync>>,
 }
 
 impl<T: DmaClient> LowerVtlMemorySpawner<T> {
@@ -88,24 +179,322 @@ pub fn new(spawner: T, vtl_protect: Arc<dyn VtlMemoryProtection + Send + Sync>)
         Self {
             spawner,
             vtl_protect,
+            live_pages: Default::default(),
+            verifier: None,
         }
     }
-}
 
-impl<T: DmaClient> DmaClient for LowerVtlMemorySpawner<T> {
-    fn allocate_dma_buffer(&self, len: usize) -> Result<MemoryBlock> {
+    /// Verifies, in debug builds, that the permissions of each allocation
+    /// were lowered, by checking the lower VTL's access through `verifier`.
+    pub fn with_verifier(mut self, verifier: Arc<dyn CheckVtlMemoryAccess + Send + Sync>) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Allocates a DMA buffer of `len` bytes, lowering the VTL permissions
+    /// only on the pages at indices `pages` within the buffer.
+    ///
+    /// This is useful when only part of a buffer (e.g. a descriptor ring)
+    /// needs to be accessible to the lower VTL.
+    pub fn allocate_dma_buffer_partial(
+        &self,
+        len: usize,
+        pages: Range<usize>,
+    ) -> Result<MemoryBlock> {
         let mem = self.spawner.allocate_dma_buffer(len)?;
-        let vtl_guard =
-            PagesAccessibleToLowerVtl::new_from_pages(self.vtl_protect.clone(), mem.pfns())
-                .context("failed to lower VTL permissions on memory block")?;
+        self.lower(mem, pages)
+    }
+
+    fn lower(&self, mem: MemoryBlock, pages: Range<usize>) -> Result<MemoryBlock> {
+        let pfns = mem.pfns().get(pages.clone()).with_context(|| {
+            format!(
+                "page range {pages:?} out of bounds for {} page memory block",
+                mem.pfns().len()
+            )
+        })?;
+        let reservation = LoweredPageReservation::new(self.live_pages.clone(), pfns)
+            .context("overlapping DMA allocation")?;
+        let vtl_guard = PagesAccessibleToLowerVtl::new_from_pages(self.vtl_protect.clone(), pfns)
+            .context("failed to lower VTL permissions on memory block")?;
+        if cfg!(debug_assertions)
+            && let Some(verifier) = &self.verifier
+        {
+            vtl_guard
+                .verify_protections(verifier.as_ref(), hvdef::HV_MAP_GPA_PERMISSIONS_ALL)
+                .context("VTL permissions were not lowered on memory block")?;
+        }
 
         Ok(MemoryBlock::new(LowerVtlDmaBuffer {
-            block: mem,
             _vtl_guard: vtl_guard,
+            _reservation: reservation,
+            block: mem,
         }))
     }
+}
+
+impl<T: DmaClient> DmaClient for LowerVtlMemorySpawner<T> {
+    fn allocate_dma_buffer(&self, len: usize) -> Result<MemoryBlock> {
+        let mem = self.spawner.allocate_dma_buffer(len)?;
+        let page_count = mem.pfns().len();
+        self.lower(mem, 0..page_count)
+    }
 
     fn attach_pending_buffers(&self) -> Result<Vec<MemoryBlock>> {
         anyhow::bail!("restore is not supported for LowerVtlMemorySpawner")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory_range::MemoryRange;
+    use page_pool_alloc::PagePool;
+    use page_pool_alloc::TestMapper;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+
+    /// A [`VtlMemoryProtection`] that records page permissions in memory.
+    #[derive(Default)]
+    struct FakeVtlMemoryProtection {
+        pages: Mutex<HashMap<u64, hvdef::HvMapGpaFlags>>,
+        /// Every call to `modify_vtl_page_setting`, in order.
+        calls: Mutex<Vec<(u64, hvdef::HvMapGpaFlags)>>,
+        /// The number of `modify_vtl_page_setting` calls preceding each call
+        /// to `flush_vtl_page_settings`.
+        flushes: Mutex<Vec<usize>>,
+        /// Ignore permission changes, simulating protections that did not
+        /// take effect.
+        ignore_writes: bool,
+        /// Fail requests to restore [`hvdef::HV_MAP_GPA_PERMISSIONS_NONE`].
+        fail_restore: AtomicBool,
+        /// Fail the next call to `flush_vtl_page_settings`.
+        fail_flush: AtomicBool,
+    }
+
+    impl VtlMemoryProtection for FakeVtlMemoryProtection {
+        fn modify_vtl_page_setting(
+            &self,
+            pfn: u64,
+            flags: hvdef::HvMapGpaFlags,
+        ) -> anyhow::Result<()> {
+            self.calls.lock().push((pfn, flags));
+            if flags == hvdef::HV_MAP_GPA_PERMISSIONS_NONE
+                && self.fail_restore.load(Ordering::Relaxed)
+            {
+                anyhow::bail!("injected restore failure");
+            }
+            if !self.ignore_writes {
+                self.pages.lock().insert(pfn, flags);
+            }
+            Ok(())
+        }
+
+        fn flush_vtl_page_settings(&self) -> anyhow::Result<()> {
+            self.flushes.lock().push(self.calls.lock().len());
+            if self.fail_flush.swap(false, Ordering::Relaxed) {
+                anyhow::bail!("injected flush failure");
+            }
+            Ok(())
+        }
+    }
+
+    impl CheckVtlMemoryAccess for FakeVtlMemoryProtection {
+        fn check_access(&self, pfn: u64, flags: hvdef::HvMapGpaFlags) -> Result<bool> {
+            let current = self
+                .pages
+                .lock()
+                .get(&pfn)
+                .copied()
+                .unwrap_or(hvdef::HV_MAP_GPA_PERMISSIONS_NONE);
+            Ok(u32::from(current) & u32::from(flags) == u32::from(flags))
+        }
+    }
+
+    /// Creates a page pool backed by a [`TestMapper`], whose memory range
+    /// must start at zero to match the mapper's backing.
+    fn test_pool() -> PagePool {
+        PagePool::new(
+            &[MemoryRange::from_4k_gpn_range(0..0x10)],
+            TestMapper::new(0x10).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_guard_lifecycle() {
+        use hvdef::HV_MAP_GPA_PERMISSIONS_ALL as ALL;
+        use hvdef::HV_MAP_GPA_PERMISSIONS_NONE as NONE;
+
+        let vtl_protect = Arc::new(FakeVtlMemoryProtection::default());
+        let guard =
+            PagesAccessibleToLowerVtl::new_from_pages(vtl_protect.clone(), &[3, 4]).unwrap();
+        assert_eq!(*vtl_protect.calls.lock(), [(3, ALL), (4, ALL)]);
+
+        drop(guard);
+        assert_eq!(
+            *vtl_protect.calls.lock(),
+            [(3, ALL), (4, ALL), (3, NONE), (4, NONE)]
+        );
+    }
+
+    #[test]
+    fn test_guard_flushes_protections() {
+        let vtl_protect = Arc::new(FakeVtlMemoryProtection::default());
+        let guard =
+            PagesAccessibleToLowerVtl::new_from_pages(vtl_protect.clone(), &[3, 4]).unwrap();
+        // Flushed once both pages are lowered.
+        assert_eq!(*vtl_protect.flushes.lock(), [2]);
+
+        // Flushed again once both pages are restored.
+        drop(guard);
+        assert_eq!(*vtl_protect.flushes.lock(), [2, 4]);
+    }
+
+    #[test]
+    fn test_guard_flush_failure_restores() {
+        use hvdef::HV_MAP_GPA_PERMISSIONS_ALL as ALL;
+        use hvdef::HV_MAP_GPA_PERMISSIONS_NONE as NONE;
+
+        let vtl_protect = Arc::new(FakeVtlMemoryProtection {
+            fail_flush: AtomicBool::new(true),
+            ..Default::default()
+        });
+        PagesAccessibleToLowerVtl::new_from_pages(vtl_protect.clone(), &[3, 4]).unwrap_err();
+
+        // The lowered pages were restored and flushed.
+        assert_eq!(
+            *vtl_protect.calls.lock(),
+            [(3, ALL), (4, ALL), (3, NONE), (4, NONE)]
+        );
+        assert_eq!(*vtl_protect.flushes.lock(), [2, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to reset page protections")]
+    fn test_guard_restore_failure_panics() {
+        let vtl_protect = Arc::new(FakeVtlMemoryProtection::default());
+        let guard =
+            PagesAccessibleToLowerVtl::new_from_pages(vtl_protect.clone(), &[3, 4]).unwrap();
+        vtl_protect.fail_restore.store(true, Ordering::Relaxed);
+        drop(guard);
+    }
+
+    #[test]
+    fn test_verify_protections() {
+        let vtl_protect = Arc::new(FakeVtlMemoryProtection::default());
+        let guard =
+            PagesAccessibleToLowerVtl::new_from_pages(vtl_protect.clone(), &[1, 2]).unwrap();
+        guard
+            .verify_protections(vtl_protect.as_ref(), hvdef::HV_MAP_GPA_PERMISSIONS_ALL)
+            .unwrap();
+
+        // Protections that did not take effect are detected.
+        let vtl_protect = Arc::new(FakeVtlMemoryProtection {
+            ignore_writes: true,
+            ..Default::default()
+        });
+        let guard =
+            PagesAccessibleToLowerVtl::new_from_pages(vtl_protect.clone(), &[1, 2]).unwrap();
+        guard
+            .verify_protections(vtl_protect.as_ref(), hvdef::HV_MAP_GPA_PERMISSIONS_ALL)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_spawner_verifier() {
+        let pool = test_pool();
+        let vtl_protect = Arc::new(FakeVtlMemoryProtection {
+            ignore_writes: true,
+            ..Default::default()
+        });
+        let spawner =
+            LowerVtlMemorySpawner::new(pool.allocator("test".into()).unwrap(), vtl_protect.clone())
+                .with_verifier(vtl_protect);
+
+        // Verification only runs in debug builds.
+        let result = spawner.allocate_dma_buffer(hvdef::HV_PAGE_SIZE as usize);
+        assert_eq!(result.is_err(), cfg!(debug_assertions));
+    }
+
+    #[test]
+    fn test_partial_lowering() {
+        let pool = test_pool();
+        let vtl_protect = Arc::new(FakeVtlMemoryProtection::default());
+        let spawner =
+            LowerVtlMemorySpawner::new(pool.allocator("test".into()).unwrap(), vtl_protect.clone());
+
+        let mem = spawner
+            .allocate_dma_buffer_partial(4 * hvdef::HV_PAGE_SIZE as usize, 1..3)
+            .unwrap();
+        let pfns = mem.pfns().to_vec();
+        for (i, pfn) in pfns.iter().enumerate() {
+            let allowed = vtl_protect
+                .check_access(*pfn, hvdef::HV_MAP_GPA_PERMISSIONS_ALL)
+                .unwrap();
+            assert_eq!(allowed, (1..3).contains(&i));
+        }
+        assert_eq!(vtl_protect.pages.lock().len(), 2);
+
+        // Only the lowered pages are restored.
+        drop(mem);
+        let pages = vtl_protect.pages.lock();
+        assert_eq!(pages.len(), 2);
+        assert!(
+            pages
+                .values()
+                .all(|flags| *flags == hvdef::HV_MAP_GPA_PERMISSIONS_NONE)
+        );
+        drop(pages);
+
+        // Out of range pages are rejected.
+        spawner
+            .allocate_dma_buffer_partial(hvdef::HV_PAGE_SIZE as usize, 0..2)
+            .unwrap_err();
+    }
+
+    /// A [`DmaClient`] that returns the same memory block for every
+    /// allocation.
+    #[derive(Inspect)]
+    struct RepeatingDmaClient {
+        #[inspect(skip)]
+        block: MemoryBlock,
+    }
+
+    impl DmaClient for RepeatingDmaClient {
+        fn allocate_dma_buffer(&self, _len: usize) -> Result<MemoryBlock> {
+            Ok(self.block.clone())
+        }
+
+        fn attach_pending_buffers(&self) -> Result<Vec<MemoryBlock>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn test_overlapping_allocations() {
+        let block = test_pool()
+            .allocator("test".into())
+            .unwrap()
+            .allocate_dma_buffer(2 * hvdef::HV_PAGE_SIZE as usize)
+            .unwrap();
+        let vtl_protect = Arc::new(FakeVtlMemoryProtection::default());
+        let spawner = LowerVtlMemorySpawner::new(RepeatingDmaClient { block }, vtl_protect.clone());
+
+        let mem = spawner
+            .allocate_dma_buffer(2 * hvdef::HV_PAGE_SIZE as usize)
+            .unwrap();
+        let calls = vtl_protect.calls.lock().len();
+        spawner
+            .allocate_dma_buffer(2 * hvdef::HV_PAGE_SIZE as usize)
+            .unwrap_err();
+        // The failed allocation did not touch any protections.
+        assert_eq!(vtl_protect.calls.lock().len(), calls);
+
+        // Once the first allocation is released, the pages can be reused.
+        drop(mem);
+        spawner
+            .allocate_dma_buffer(2 * hvdef::HV_PAGE_SIZE as usize)
+            .unwrap();
+    }
+}
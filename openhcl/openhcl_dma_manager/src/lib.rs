@@ -11,6 +11,7 @@
 use anyhow::Context;
 use hcl_mapper::HclMapper;
 use inspect::Inspect;
+use lower_vtl_permissions_guard::CheckVtlMemoryAccess;
 use lower_vtl_permissions_guard::LowerVtlMemorySpawner;
 use memory_range::MemoryRange;
 use page_pool_alloc::PagePool;
@@ -174,9 +175,18 @@ struct DmaManagerLowerVtl {
 impl DmaManagerLowerVtl {
     pub fn new() -> anyhow::Result<Arc<Self>> {
         let mshv_hvcall = hcl::ioctl::MshvHvcall::new().context("failed to open mshv_hvcall")?;
-        mshv_hvcall.set_allowed_hypercalls(&[hvdef::HypercallCode::HvCallModifyVtlProtectionMask]);
+        mshv_hvcall.set_allowed_hypercalls(&[
+            hvdef::HypercallCode::HvCallModifyVtlProtectionMask,
+            hvdef::HypercallCode::HvCallCheckSparseGpaPageVtlAccess,
+        ]);
         Ok(Arc::new(Self { mshv_hvcall }))
     }
+
+    /// Wraps `spawner` so that its allocations are accessible to VTL0. In
+    /// debug builds, the access is verified after each allocation.
+    fn spawner<T: DmaClient>(self: &Arc<Self>, spawner: T) -> LowerVtlMemorySpawner<T> {
+        LowerVtlMemorySpawner::new(spawner, self.clone()).with_verifier(self.clone())
+    }
 }
 
 impl virt::VtlMemoryProtection for DmaManagerLowerVtl {
@@ -191,6 +201,16 @@ fn modify_vtl_page_setting(&self, pfn: u64, flags: hvdef::HvMapGpaFlags) -> anyh
     }
 }
 
+impl CheckVtlMemoryAccess for DmaManagerLowerVtl {
+    fn check_access(&self, pfn: u64, flags: hvdef::HvMapGpaFlags) -> anyhow::Result<bool> {
+        let result = self
+            .mshv_hvcall
+            .check_vtl_access(pfn * hvdef::HV_PAGE_SIZE, hvdef::Vtl::Vtl0.into(), flags)
+            .context("failed to check VTL page permissions")?;
+        Ok(result.is_none())
+    }
+}
+
 impl DmaManagerInner {
     fn new_dma_client(&self, params: DmaClientParameters) -> anyhow::Result<Arc<OpenhclDmaClient>> {
         // Allocate the inner client that actually performs the allocations.
@@ -260,17 +280,18 @@ struct ClientCreation<'a> {
                     LowerVtlPermissionPolicy::Vtl0 => {
                         // Private memory must be wrapped in a lower VTL memory
                         // spawner, as otherwise it is accessible to VTL2 only.
-                        DmaClientBacking::PrivatePoolLowerVtl(LowerVtlMemorySpawner::new(
-                            private
-                                .allocator(device_name.into())
-                                .context("failed to create private allocator")?,
+                        DmaClientBacking::PrivatePoolLowerVtl(
                             self.lower_vtl
                                 .as_ref()
                                 .ok_or(anyhow::anyhow!(
                                     "lower vtl not available on hardware isolated platforms"
                                 ))?
-                                .clone(),
-                        ))
+                                .spawner(
+                                    private
+                                        .allocator(device_name.into())
+                                        .context("failed to create private allocator")?,
+                                ),
+                        )
                     }
                 },
                 ClientCreation {
@@ -296,15 +317,14 @@ struct ClientCreation<'a> {
                     LowerVtlPermissionPolicy::Vtl0 => {
                         // `LockedMemorySpawner` uses private VTL2 ram, so
                         // lowering VTL permissions is required.
-                        DmaClientBacking::LockedMemoryLowerVtl(LowerVtlMemorySpawner::new(
-                            LockedMemorySpawner,
+                        DmaClientBacking::LockedMemoryLowerVtl(
                             self.lower_vtl
                                 .as_ref()
                                 .ok_or(anyhow::anyhow!(
                                     "lower vtl not available on hardware isolated platforms"
                                 ))?
-                                .clone(),
-                        ))
+                                .spawner(LockedMemorySpawner),
+                        )
                     }
                 },
             }
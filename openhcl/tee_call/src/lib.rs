@@ -97,7 +97,7 @@ fn get_attestation_report(
     ) -> Result<GetAttestationReportResult, Error> {
         let dev = sev_guest_device::SevGuestDevice::open().map_err(Error::OpenDevSevGuest)?;
         let report = dev
-            .get_report(*report_data, 0)
+            .get_verified_report(*report_data, 0)
             .map_err(Error::GetSnpReport)?;
 
         Ok(GetAttestationReportResult {
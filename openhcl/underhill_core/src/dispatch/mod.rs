@@ -473,7 +473,7 @@ enum Event<T> {
                     {
                         tracing::error!(
                             CVM_ALLOWED,
-                            error = err.as_ref() as &dyn std::error::Error,
+                            error = &err as &dyn std::error::Error,
                             "failed to process VPCI relay"
                         );
                     }
@@ -190,6 +190,10 @@ pub struct Options {
 
     /// (OPENHCL_ENABLE_VPCI_RELAY=1) Enable the VPCI relay.
     pub enable_vpci_relay: Option<bool>,
+
+    /// (OPENHCL_VPCI_RELAY_TRACE_MMIO=1) Trace the MMIO traffic of the VPCI
+    /// relay, for debugging.
+    pub vpci_relay_trace_mmio: bool,
 }
 
 impl Options {
@@ -328,6 +332,7 @@ fn parse_number(value: Option<&OsString>) -> anyhow::Result<Option<u64>> {
         let enable_vpci_relay = parse_env_bool_opt("OPENHCL_ENABLE_VPCI_RELAY")
             .ok()
             .flatten();
+        let vpci_relay_trace_mmio = parse_env_bool("OPENHCL_VPCI_RELAY_TRACE_MMIO");
 
         let mut args = std::env::args().chain(extra_args);
         // Skip our own filename.
@@ -388,6 +393,7 @@ fn parse_number(value: Option<&OsString>) -> anyhow::Result<Option<u64>> {
             guest_state_encryption_policy,
             attempt_ak_cert_callback,
             enable_vpci_relay,
+            vpci_relay_trace_mmio,
         })
     }
 
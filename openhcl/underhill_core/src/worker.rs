@@ -298,6 +298,8 @@ pub struct UnderhillEnvCfg {
     pub attempt_ak_cert_callback: Option<bool>,
     /// Enable the VPCI relay
     pub enable_vpci_relay: Option<bool>,
+    /// Trace the MMIO traffic of the VPCI relay
+    pub vpci_relay_trace_mmio: bool,
 }
 
 /// Bundle of config + runtime objects for hooking into the underhill remote
@@ -2881,6 +2883,21 @@ async fn new_underhill_vm(
             if enable_vpci_relay {
                 use vpci_relay::*;
 
+                let mut mmio_access: Box<dyn CreateMemoryAccess> = if use_mmio_hypercalls {
+                    Box::new(
+                        linux_mmio::HypercallMmio::new()
+                            .context("failed to create hypercall mmio accessor")?,
+                    )
+                } else {
+                    Box::new(
+                        linux_mmio::DirectMmio::new()
+                            .context("failed to create direct mmio accessor")?,
+                    )
+                };
+                if env_cfg.vpci_relay_trace_mmio {
+                    mmio_access = Box::new(tracing_mmio::TracingMmio::new(mmio_access));
+                }
+
                 let mut relay = VpciRelay::new(
                     driver_source.clone(),
                     vpci_filter.take(),
@@ -2896,17 +2913,7 @@ async fn new_underhill_vm(
                         persistent_allocations: false,
                     })?,
                     vpci_relay_mmio,
-                    if use_mmio_hypercalls {
-                        Box::new(
-                            linux_mmio::HypercallMmio::new()
-                                .context("failed to create hypercall mmio accessor")?,
-                        )
-                    } else {
-                        Box::new(
-                            linux_mmio::DirectMmio::new()
-                                .context("failed to create direct mmio accessor")?,
-                        )
-                    },
+                    mmio_access,
                 );
 
                 // Allow NVMe devices.
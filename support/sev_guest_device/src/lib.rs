@@ -7,6 +7,7 @@
 #![expect(unsafe_code)]
 
 use std::fs::File;
+use std::marker::PhantomData;
 use std::os::fd::AsRawFd;
 use thiserror::Error;
 use x86defs::snp::SNP_DERIVED_KEY_SIZE;
@@ -29,15 +30,83 @@
 /// The size of the response data defined by the Linux kernel.
 const LINUX_SNP_REPORT_RESP_DATA_SIZE: usize = 4000;
 
+/// The maximum number of attempts for a guest request ioctl that keeps failing
+/// with a transient error.
+const MAX_GUEST_REQUEST_ATTEMPTS: usize = 5;
+
 #[expect(missing_docs)] // self-explanatory fields
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("failed to open /dev/sev-guest")]
     OpenDevSevGuest(#[source] std::io::Error),
-    #[error("SNP_GET_REPORT ioctl failed")]
-    SnpGetReportIoctl(#[source] nix::Error),
-    #[error("SNP_GET_DERIVED_KEY ioctl failed")]
-    SnpGetDerivedKeyIoctl(#[source] nix::Error),
+    #[error("SNP_GET_REPORT ioctl failed, fw_error: {fw_error:#x?}, vmm_error: {vmm_error:#x?}")]
+    SnpGetReportIoctl {
+        #[source]
+        err: nix::Error,
+        fw_error: FirmwareError,
+        vmm_error: VmmError,
+    },
+    #[error(
+        "SNP_GET_DERIVED_KEY ioctl failed, fw_error: {fw_error:#x?}, vmm_error: {vmm_error:#x?}"
+    )]
+    SnpGetDerivedKeyIoctl {
+        #[source]
+        err: nix::Error,
+        fw_error: FirmwareError,
+        vmm_error: VmmError,
+    },
+    #[error("SNP report does not echo the requested report data")]
+    ReportDataMismatch,
+}
+
+open_enum::open_enum! {
+    /// Firmware status codes reported by a failed guest request.
+    /// See "Status Codes", "SEV Secure Nested Paging Firmware ABI specification", Revision 1.55.
+    pub enum FirmwareError: u32 {
+        #![expect(missing_docs)] // self-explanatory variants
+        SUCCESS = 0x00,
+        INVALID_PLATFORM_STATE = 0x01,
+        INVALID_GUEST_STATE = 0x02,
+        INVALID_CONFIG = 0x03,
+        INVALID_LENGTH = 0x04,
+        ALREADY_OWNED = 0x05,
+        INVALID_CERTIFICATE = 0x06,
+        POLICY_FAILURE = 0x07,
+        INACTIVE = 0x08,
+        INVALID_ADDRESS = 0x09,
+        BAD_SIGNATURE = 0x0a,
+        BAD_MEASUREMENT = 0x0b,
+        ASID_OWNED = 0x0c,
+        INVALID_ASID = 0x0d,
+        WBINVD_REQUIRED = 0x0e,
+        DF_FLUSH_REQUIRED = 0x0f,
+        INVALID_GUEST = 0x10,
+        INVALID_COMMAND = 0x11,
+        ACTIVE = 0x12,
+        HWERROR_PLATFORM = 0x13,
+        HWERROR_UNSAFE = 0x14,
+        UNSUPPORTED = 0x15,
+        INVALID_PARAM = 0x16,
+        RESOURCE_LIMIT = 0x17,
+        SECURE_DATA_INVALID = 0x18,
+        INVALID_PAGE_SIZE = 0x19,
+        INVALID_PAGE_STATE = 0x1a,
+        INVALID_MDATA_ENTRY = 0x1b,
+        INVALID_PAGE_OWNER = 0x1c,
+        AEAD_OFLOW = 0x1d,
+    }
+}
+
+open_enum::open_enum! {
+    /// VMM error codes reported by a failed guest request, defined by Linux as
+    /// `SNP_GUEST_VMM_ERR_*`.
+    pub enum VmmError: u32 {
+        #![expect(missing_docs)] // self-explanatory variants
+        NONE = 0,
+        INVALID_LEN = 1,
+        BUSY = 2,
+        GENERIC = !0,
+    }
 }
 
 /// Ioctl struct defined by Linux.
@@ -53,6 +122,44 @@ struct SnpGuestRequestIoctl {
     exitinfo: VmmErrorCode,
 }
 
+/// An [`SnpGuestRequestIoctl`] that borrows its request and response buffers.
+///
+/// The ioctl struct only carries the buffer addresses, so nothing otherwise
+/// ties the buffers' lifetimes to the ioctl. Holding the borrows here
+/// guarantees that the request stays alive and the response stays alive and
+/// exclusively borrowed (since the kernel writes to it) for as long as the
+/// ioctl struct can be passed to the kernel.
+struct GuestRequest<'a> {
+    ioctl: SnpGuestRequestIoctl,
+    _buffers: PhantomData<(&'a [u8], &'a mut [u8])>,
+}
+
+impl<'a> GuestRequest<'a> {
+    fn new<Req, Resp>(req: &'a Req, resp: &'a mut Resp) -> Self
+    where
+        Req: IntoBytes + Immutable,
+        Resp: IntoBytes + FromBytes,
+    {
+        Self {
+            ioctl: SnpGuestRequestIoctl {
+                msg_version: SNP_GUEST_REQ_MSG_VERSION,
+                req_data: req.as_bytes().as_ptr() as u64,
+                resp_data: resp.as_mut_bytes().as_mut_ptr() as u64,
+                exitinfo: VmmErrorCode::new_zeroed(),
+            },
+            _buffers: PhantomData,
+        }
+    }
+
+    /// Returns the firmware and VMM errors reported by the last ioctl.
+    fn errors(&self) -> (FirmwareError, VmmError) {
+        (
+            FirmwareError(self.ioctl.exitinfo.fw_error),
+            VmmError(self.ioctl.exitinfo.vmm_error),
+        )
+    }
+}
+
 /// VMM error code.
 #[repr(C)]
 #[derive(FromZeros, Immutable, KnownLayout)]
@@ -119,24 +226,37 @@ pub fn open() -> Result<Self, Error> {
             rsvd: [0u8; 28],
         };
 
-        let resp = SnpReportIoctlResp::new_zeroed();
+        let mut resp = SnpReportIoctlResp::new_zeroed();
+        let mut request = GuestRequest::new(&req, &mut resp);
 
-        let mut snp_guest_request = SnpGuestRequestIoctl {
-            msg_version: SNP_GUEST_REQ_MSG_VERSION,
-            req_data: req.as_bytes().as_ptr() as u64,
-            resp_data: resp.as_bytes().as_ptr() as u64,
-            exitinfo: VmmErrorCode::new_zeroed(),
-        };
-
-        // SAFETY: Make SNP_GET_REPORT ioctl call to the device with correct types.
-        unsafe {
-            snp_get_report(self.file.as_raw_fd(), &mut snp_guest_request)
-                .map_err(Error::SnpGetReportIoctl)?;
-        }
+        retry_transient(|| {
+            // SAFETY: Make SNP_GET_REPORT ioctl call to the device with correct
+            // types. The request borrows the buffers it points to.
+            unsafe { snp_get_report(self.file.as_raw_fd(), &mut request.ioctl) }
+        })
+        .map_err(|err| {
+            let (fw_error, vmm_error) = request.errors();
+            Error::SnpGetReportIoctl {
+                err,
+                fw_error,
+                vmm_error,
+            }
+        })?;
 
         Ok(resp.report.report)
     }
 
+    /// Invoke the `SNP_GET_REPORT` ioctl via the device, and verify that the
+    /// returned report echoes `user_data` in its `report_data` field.
+    ///
+    /// Use this when `user_data` is a nonce, to reject a stale or replayed
+    /// report.
+    pub fn get_verified_report(&self, user_data: [u8; 64], vmpl: u32) -> Result<SnpReport, Error> {
+        let report = self.get_report(user_data, vmpl)?;
+        check_report_data(&report, &user_data)?;
+        Ok(report)
+    }
+
     /// Invoke the `SNP_GET_DERIVED_KEY` ioctl via the device.
     pub fn get_derived_key(
         &self,
@@ -155,21 +275,159 @@ pub fn get_derived_key(
             tcb_version,
         };
 
-        let resp = SnpDerivedKeyResp::new_zeroed();
+        let mut resp = SnpDerivedKeyResp::new_zeroed();
+        let mut request = GuestRequest::new(&req, &mut resp);
 
-        let mut snp_guest_request = SnpGuestRequestIoctl {
-            msg_version: SNP_GUEST_REQ_MSG_VERSION,
-            req_data: req.as_bytes().as_ptr() as u64,
-            resp_data: resp.as_bytes().as_ptr() as u64,
-            exitinfo: VmmErrorCode::new_zeroed(),
-        };
+        retry_transient(|| {
+            // SAFETY: Make SNP_GET_DERIVED_KEY ioctl call to the device with
+            // correct types. The request borrows the buffers it points to.
+            unsafe { snp_get_derived_key(self.file.as_raw_fd(), &mut request.ioctl) }
+        })
+        .map_err(|err| {
+            let (fw_error, vmm_error) = request.errors();
+            Error::SnpGetDerivedKeyIoctl {
+                err,
+                fw_error,
+                vmm_error,
+            }
+        })?;
+
+        Ok(resp.derived_key)
+    }
+}
 
-        // SAFETY: Make SNP_GET_DERIVED_KEY ioctl call to the device with correct types
-        unsafe {
-            snp_get_derived_key(self.file.as_raw_fd(), &mut snp_guest_request)
-                .map_err(Error::SnpGetReportIoctl)?;
+/// Invokes `f`, retrying up to [`MAX_GUEST_REQUEST_ATTEMPTS`] times while it
+/// fails with `EINTR` or `EAGAIN`, which are expected on signal delivery.
+fn retry_transient<T>(mut f: impl FnMut() -> nix::Result<T>) -> nix::Result<T> {
+    let mut attempts = 1;
+    loop {
+        match f() {
+            Err(nix::Error::EINTR | nix::Error::EAGAIN)
+                if attempts < MAX_GUEST_REQUEST_ATTEMPTS =>
+            {
+                attempts += 1;
+            }
+            r => break r,
         }
+    }
+}
 
-        Ok(resp.derived_key)
+/// Checks that `report` carries `user_data` as its report data.
+fn check_report_data(report: &SnpReport, user_data: &[u8; 64]) -> Result<(), Error> {
+    if report.report_data != *user_data {
+        return Err(Error::ReportDataMismatch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_vmm_error_code() {
+        let err = Error::SnpGetReportIoctl {
+            err: nix::Error::EIO,
+            fw_error: FirmwareError(0x16),
+            vmm_error: VmmError(0),
+        };
+        assert_eq!(
+            err.to_string(),
+            "SNP_GET_REPORT ioctl failed, fw_error: INVALID_PARAM, vmm_error: NONE"
+        );
+
+        let err = Error::SnpGetDerivedKeyIoctl {
+            err: nix::Error::EIO,
+            fw_error: FirmwareError(0x1234),
+            vmm_error: VmmError(2),
+        };
+        assert_eq!(
+            err.to_string(),
+            "SNP_GET_DERIVED_KEY ioctl failed, fw_error: 0x1234, vmm_error: BUSY"
+        );
+    }
+
+    #[test]
+    fn report_data_echo() {
+        let nonce = [0x5a; 64];
+        let mut report = SnpReport::new_zeroed();
+        report.report_data = nonce;
+        check_report_data(&report, &nonce).unwrap();
+
+        let mut stale = nonce;
+        stale[0] = 0;
+        assert!(matches!(
+            check_report_data(&report, &stale),
+            Err(Error::ReportDataMismatch)
+        ));
+    }
+
+    #[test]
+    fn raw_report_round_trip() {
+        let mut report = SnpReport::new_zeroed();
+        report.version = 2;
+        report.report_data = [0x5a; 64];
+
+        // Verifiers expect the ABI layout, with REPORT_DATA at offset 0x50.
+        let bytes = report.as_bytes();
+        assert_eq!(bytes.len(), x86defs::snp::SNP_REPORT_SIZE);
+        assert_eq!(bytes[0..4], 2u32.to_le_bytes());
+        assert_eq!(bytes[0x50..0x90], [0x5a; 64]);
+
+        let parsed = SnpReport::read_from_bytes(bytes).unwrap();
+        assert_eq!(parsed.as_bytes(), bytes);
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        // A transient error is retried.
+        let mut results = [Err(nix::Error::EINTR), Err(nix::Error::EAGAIN), Ok(5)].into_iter();
+        assert_eq!(retry_transient(|| results.next().unwrap()), Ok(5));
+
+        // Other errors are not.
+        let mut calls = 0;
+        let r: nix::Result<()> = retry_transient(|| {
+            calls += 1;
+            Err(nix::Error::EIO)
+        });
+        assert_eq!(r, Err(nix::Error::EIO));
+        assert_eq!(calls, 1);
+
+        // Retries are bounded.
+        let mut calls = 0;
+        let r: nix::Result<()> = retry_transient(|| {
+            calls += 1;
+            Err(nix::Error::EINTR)
+        });
+        assert_eq!(r, Err(nix::Error::EINTR));
+        assert_eq!(calls, MAX_GUEST_REQUEST_ATTEMPTS);
+    }
+
+    #[test]
+    fn guest_request_buffers() {
+        let req = SnpDerivedKeyReq {
+            root_key_select: 1,
+            rsvd: 0,
+            guest_field_select: 2,
+            vmpl: 3,
+            guest_svn: 4,
+            tcb_version: 5,
+        };
+        let mut resp = SnpDerivedKeyResp::new_zeroed();
+        let mut request = GuestRequest::new(&req, &mut resp);
+        assert_eq!(request.ioctl.req_data, req.as_bytes().as_ptr() as u64);
+
+        // Act as the kernel: write the response through the ioctl struct.
+        let resp_data = request.ioctl.resp_data as *mut SnpDerivedKeyResp;
+        // SAFETY: resp_data points to `resp`, which the request exclusively
+        // borrows.
+        unsafe { (*resp_data).derived_key = [0xaa; SNP_DERIVED_KEY_SIZE] };
+        request.ioctl.exitinfo.fw_error = 0x16;
+
+        assert_eq!(
+            request.errors(),
+            (FirmwareError::INVALID_PARAM, VmmError::NONE)
+        );
+        assert_eq!(resp.derived_key, [0xaa; SNP_DERIVED_KEY_SIZE]);
     }
 }
@@ -5,15 +5,28 @@
 
 #![expect(missing_docs)]
 
+use crate::bus::VpciBusDevice;
+use chipset_device::ChipsetDevice;
+use chipset_device::mmio::ExternallyManagedMmioIntercepts;
+use closeable_mutex::CloseableMutex;
+use guestmem::GuestMemory;
+use guid::Guid;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
 use parking_lot::Mutex;
 use pci_core::msi::MsiControl;
 use pci_core::msi::MsiInterruptTarget;
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
 use std::sync::Arc;
+use task_control::StopTask;
+use vmbus_channel::RawAsyncChannel;
+use vmbus_channel::simple::SimpleVmbusDevice;
+use vmbus_ring::FlatRingMem;
 use vmcore::vpci_msi::MapVpciInterrupt;
 use vmcore::vpci_msi::MsiAddressData;
 use vmcore::vpci_msi::RegisterInterruptError;
+use vmcore::vpci_msi::VpciInterruptMapper;
 use vmcore::vpci_msi::VpciInterruptParameters;
 
 #[derive(Debug, Clone)]
@@ -99,6 +112,36 @@ async fn unregister_interrupt(&self, address: u64, data: u32) {
     }
 }
 
+/// Creates a VPCI bus for `device` and serves its vmbus channel on a task
+/// spawned on `driver`.
+///
+/// Returns the bus, the guest end of the channel to connect a client to, and
+/// the task serving the channel, which must be kept alive.
+pub fn spawn_test_bus(
+    driver: &impl Spawn,
+    device: Arc<CloseableMutex<dyn ChipsetDevice>>,
+) -> (VpciBusDevice, RawAsyncChannel<FlatRingMem>, Task<()>) {
+    let (bus, mut channel) = VpciBusDevice::new(
+        Guid::new_random(),
+        device,
+        &mut ExternallyManagedMmioIntercepts,
+        VpciInterruptMapper::new(TestVpciInterruptController::new()),
+    )
+    .unwrap();
+
+    let (host, guest) = vmbus_channel::connected_async_channels(32768);
+
+    let mut runner = channel.open(host, GuestMemory::empty()).unwrap();
+    let task = driver.spawn("server", async move {
+        StopTask::run_with(std::future::pending(), async |stop| {
+            let _ = channel.run(stop, &mut runner).await;
+        })
+        .await
+    });
+
+    (bus, guest, task)
+}
+
 #[derive(Debug)]
 struct MsiInterrupt {
     _address: u64,
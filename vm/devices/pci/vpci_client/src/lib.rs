@@ -22,6 +22,7 @@
 use pal_async::task::Spawn;
 use pal_async::task::Task;
 use parking_lot::Mutex;
+use pci_core::spec::cfg_space::BarEncodingBits;
 use pci_core::spec::cfg_space::Command;
 use pci_core::spec::cfg_space::HeaderType00;
 use pci_core::spec::hwid::HardwareIds;
@@ -352,7 +353,7 @@ pub async fn init(self) -> anyhow::Result<(VpciDevice, VpciDeviceEject)> {
                 high64 = false;
                 *rao = 0;
             } else {
-                let bits = pci_core::spec::cfg_space::BarEncodingBits::from(bar);
+                let bits = BarEncodingBits::from(bar);
                 if bits.use_pio() {
                     anyhow::bail!("BAR {} is PIO, which is not supported by VPCI", i);
                 }
@@ -407,7 +408,66 @@ fn poll_next(
     }
 }
 
+/// A memory BAR implemented by a [`VpciDevice`].
+///
+/// VPCI does not support PIO BARs, so all BARs are MMIO.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConfiguredBar {
+    /// The BAR index. For 64-bit BARs, this is the index of the low half.
+    pub index: u8,
+    /// The base address currently programmed into the BAR.
+    pub base: u64,
+    /// The size of the BAR, in 4KB pages.
+    pub size_pages: u64,
+    /// Whether this is a 64-bit BAR.
+    pub is_64bit: bool,
+    /// Whether the BAR is prefetchable.
+    pub prefetchable: bool,
+}
+
+/// Computes the implemented BARs from the BAR masks reported by the host and
+/// the BAR values programmed by the guest.
+fn configured_bars(bar_masks: &[u32; 6], bars: &[u32; 6]) -> Vec<ConfiguredBar> {
+    const PAGE_SIZE: u64 = 4096;
+
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < bar_masks.len() {
+        let bits = BarEncodingBits::from(bar_masks[i]);
+        let is_64bit = bits.type_64_bit() && i + 1 < bar_masks.len();
+        let (size, base) = if is_64bit {
+            let mask = (bar_masks[i] as u64 & !0xf) | ((bar_masks[i + 1] as u64) << 32);
+            (
+                (!mask).wrapping_add(1),
+                (bars[i] as u64 & !0xf) | ((bars[i + 1] as u64) << 32),
+            )
+        } else {
+            let mask = bar_masks[i] & !0xf;
+            ((!mask).wrapping_add(1) as u64, (bars[i] & !0xf) as u64)
+        };
+        // An unimplemented BAR has a mask of zero, which wraps to a size of
+        // zero.
+        if size != 0 {
+            result.push(ConfiguredBar {
+                index: i as u8,
+                base,
+                size_pages: size.div_ceil(PAGE_SIZE),
+                is_64bit,
+                prefetchable: bits.prefetchable(),
+            });
+        }
+        i += if is_64bit { 2 } else { 1 };
+    }
+    result
+}
+
 impl VpciDevice {
+    /// Returns the memory BARs implemented by the device, along with their
+    /// sizes and currently programmed base addresses.
+    pub fn configured_bars(&self) -> Vec<ConfiguredBar> {
+        configured_bars(&self.bar_masks, &self.shadows.lock().bars)
+    }
+
     /// Reads device configuration space.
     ///
     /// Some values will be handled without communicating with the host.
@@ -7,24 +7,17 @@
 
 use chipset_device::ChipsetDevice;
 use chipset_device::io::IoResult;
-use chipset_device::mmio::ExternallyManagedMmioIntercepts;
 use chipset_device::pci::PciConfigSpace;
 use closeable_mutex::CloseableMutex;
-use guestmem::GuestMemory;
-use guid::Guid;
 use pal_async::DefaultDriver;
 use pal_async::async_test;
-use pal_async::task::Spawn;
 use std::sync::Arc;
-use task_control::StopTask;
 use test_with_tracing::test;
-use vmbus_channel::simple::SimpleVmbusDevice;
 use vmcore::vpci_msi::MapVpciInterrupt;
 use vmcore::vpci_msi::MsiAddressData;
-use vmcore::vpci_msi::VpciInterruptMapper;
 use vmcore::vpci_msi::VpciInterruptParameters;
 use vpci::bus::VpciBusDevice;
-use vpci::test_helpers::TestVpciInterruptController;
+use vpci::test_helpers::spawn_test_bus;
 
 struct NoopDevice;
 
@@ -73,25 +66,7 @@ fn write(&mut self, addr: u64, value: u32) {
 
 #[async_test]
 async fn test_negotiate_version(driver: DefaultDriver) {
-    let device = Arc::new(CloseableMutex::new(NoopDevice));
-    let msi_controller = TestVpciInterruptController::new();
-    let (bus, mut channel) = VpciBusDevice::new(
-        Guid::new_random(),
-        device,
-        &mut ExternallyManagedMmioIntercepts,
-        VpciInterruptMapper::new(msi_controller),
-    )
-    .unwrap();
-
-    let (host, guest) = vmbus_channel::connected_async_channels(32768);
-
-    let mut runner = channel.open(host, GuestMemory::empty()).unwrap();
-    let _task = driver.spawn("server", async move {
-        StopTask::run_with(std::future::pending(), async |stop| {
-            let _ = channel.run(stop, &mut runner).await;
-        })
-        .await
-    });
+    let (bus, guest, _task) = spawn_test_bus(&driver, Arc::new(CloseableMutex::new(NoopDevice)));
 
     let (_client, devices) =
         super::VpciClient::connect(&driver, guest, Box::new(BusWrapper(bus)), mesh::channel().0)
@@ -115,3 +90,48 @@ async fn test_negotiate_version(driver: DefaultDriver) {
 
     device.unregister_interrupt(address, data).await;
 }
+
+#[test]
+fn test_configured_bars() {
+    let bar_masks = [
+        // 16KB 32-bit BAR.
+        0xffff_c000,
+        // Unimplemented.
+        0,
+        // 2MB 64-bit prefetchable BAR.
+        0xffe0_000c,
+        0xffff_ffff,
+        // 4KB 32-bit BAR.
+        0xffff_f000,
+        // Unimplemented.
+        0,
+    ];
+    let bars = [0xfe00_0000, 0, 0x8000_000c, 0x1, 0xfe01_0000, 0];
+
+    assert_eq!(
+        super::configured_bars(&bar_masks, &bars),
+        [
+            super::ConfiguredBar {
+                index: 0,
+                base: 0xfe00_0000,
+                size_pages: 4,
+                is_64bit: false,
+                prefetchable: false,
+            },
+            super::ConfiguredBar {
+                index: 2,
+                base: 0x1_8000_0000,
+                size_pages: 512,
+                is_64bit: true,
+                prefetchable: true,
+            },
+            super::ConfiguredBar {
+                index: 4,
+                base: 0xfe01_0000,
+                size_pages: 1,
+                is_64bit: false,
+                prefetchable: false,
+            },
+        ]
+    );
+}
@@ -9,6 +9,9 @@
 
 #[cfg(target_os = "linux")]
 pub mod linux_mmio;
+#[cfg(test)]
+mod test_helpers;
+pub mod tracing_mmio;
 
 // Exported to make it easier to define filters without explicitly pulling in
 // `pci_core`.
@@ -16,7 +19,6 @@
 pub use pci_core::spec::hwid::ProgrammingInterface;
 pub use pci_core::spec::hwid::Subclass;
 
-use anyhow::Context as _;
 use chipset_device::ChipsetDevice;
 use chipset_device::io::IoResult;
 use chipset_device::pci::PciConfigSpace;
@@ -29,6 +31,7 @@
 use std::future::poll_fn;
 use std::sync::Arc;
 use std::task::Poll;
+use thiserror::Error;
 use user_driver::DmaClient;
 use vmbus_client::driver::OpenParams;
 use vmbus_server::Guid;
@@ -37,6 +40,7 @@
 use vmcore::save_restore::SaveError;
 use vmcore::save_restore::SaveRestore;
 use vmcore::save_restore::SavedStateNotSupported;
+use vmcore::vm_task::VmTaskDriver;
 use vmcore::vm_task::VmTaskDriverSource;
 use vmcore::vpci_msi::VpciInterruptMapper;
 use vmotherboard::ChipsetDevices;
@@ -47,7 +51,7 @@
 use vpci_client::VpciDeviceEject;
 
 /// Trait for creating memory access instances.
-pub trait CreateMemoryAccess: 'static + Send + Sync {
+pub trait CreateMemoryAccess: 'static + Send + Sync + Inspect {
     /// Creates a new memory access instance for the given guest physical address.
     fn create_memory_access(&self, gpa: u64) -> anyhow::Result<Box<dyn MemoryAccess>>;
 }
@@ -55,6 +59,29 @@ pub trait CreateMemoryAccess: 'static + Send + Sync {
 /// The size of the MMIO region required for each VPCI device.
 pub const VPCI_RELAY_MMIO_PER_DEVICE: u64 = vpci_client::MMIO_SIZE;
 
+/// An error relaying a VPCI bus.
+#[derive(Debug, Error)]
+pub enum RelayError {
+    /// The relay's MMIO range has no room for another bus.
+    #[error("not enough MMIO space left")]
+    OutOfMmioSpace,
+    /// The MMIO accessor for the bus could not be created.
+    #[error("failed to create memory access for vpci mmio")]
+    MemoryAccess(#[source] anyhow::Error),
+    /// The vmbus channel to the host could not be opened.
+    #[error("failed to open vpci channel")]
+    OpenChannel(#[source] anyhow::Error),
+    /// The VPCI protocol connection with the host failed.
+    #[error("failed to connect to vpci bus")]
+    Connect(#[source] anyhow::Error),
+    /// The VPCI device could not be initialized.
+    #[error("failed to initialize vpci device")]
+    InitDevice(#[source] anyhow::Error),
+    /// The relayed device or bus could not be added to the chipset.
+    #[error("failed to add relayed device to the chipset")]
+    AddDevice(#[source] anyhow::Error),
+}
+
 /// Virtual PCI relay.
 #[derive(Inspect)]
 pub struct VpciRelay {
@@ -70,10 +97,10 @@ pub struct VpciRelay {
     #[inspect(iter_by_key)]
     devices: slab::Slab<RelayedDevice>,
     mmio_range: MemoryRange,
-    #[inspect(skip)]
     mmio_access: Box<dyn CreateMemoryAccess>,
     #[inspect(iter_by_index)]
     allowed_devices: Vec<AllowedDevice>,
+    target_vp: Option<u32>,
 }
 
 #[derive(Inspect)]
@@ -150,6 +177,11 @@ fn allows(&self, hw: &HardwareIds) -> bool {
 
 impl VpciRelay {
     /// Creates a new VPCI relay.
+    ///
+    /// `dma_client` is used to allocate the ring buffers of the VPCI bus
+    /// channels. Since the host accesses these rings, the client must return
+    /// memory accessible to the host, e.g. by wrapping it in a client that
+    /// lowers VTL protections on allocation.
     pub fn new(
         driver_source: VmTaskDriverSource,
         offers: vmbus_client::ConnectResult,
@@ -168,6 +200,7 @@ pub fn new(
             mmio_range,
             mmio_access,
             allowed_devices: Vec::new(),
+            target_vp: None,
         }
     }
 
@@ -179,6 +212,13 @@ pub fn add_allowed_device(&mut self, dev: AllowedDevice) {
         self.allowed_devices.push(dev);
     }
 
+    /// Sets the VP that the tasks relaying each VPCI bus should run on.
+    ///
+    /// By default, the relay uses a driver with no particular target VP.
+    pub fn set_target_vp(&mut self, target_vp: u32) {
+        self.target_vp = Some(target_vp);
+    }
+
     /// Wait for the relay to be ready. This might never return. This call is cancellable.
     pub async fn wait_ready(&mut self) {
         poll_fn(|cx| {
@@ -208,7 +248,7 @@ pub async fn process(
         &mut self,
         chipset: &ChipsetDevices,
         units: &mut StateUnits,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RelayError> {
         let mut i = 0;
         while i < self.devices.len() {
             if self.devices[i].ready_to_remove {
@@ -229,20 +269,25 @@ async fn relay_vpci_bus(
         chipset: &ChipsetDevices,
         state_units: &mut StateUnits,
         offer_info: vmbus_client::OfferInfo,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RelayError> {
         let entry = self.devices.vacant_entry();
-        if (entry.key() as u64 + 1) * vpci_client::MMIO_SIZE > self.mmio_range.len() {
-            anyhow::bail!("not enough MMIO space left");
-        }
+        let mmio_gpa = bus_mmio_gpa(self.mmio_range, entry.key())?;
 
         let instance_id = offer_info.offer.instance_id;
 
-        let mmio = self.mmio_access.create_memory_access(
-            self.mmio_range.start() + (entry.key() as u64) * vpci_client::MMIO_SIZE,
-        )?;
+        let mmio = self
+            .mmio_access
+            .create_memory_access(mmio_gpa)
+            .map_err(RelayError::MemoryAccess)?;
+
+        let driver = relay_driver(
+            &self.driver_source,
+            self.target_vp,
+            format!("vpci-relay-{instance_id}"),
+        );
 
         let channel = vmbus_client::driver::open_channel(
-            self.driver_source.simple(),
+            driver.clone(),
             offer_info,
             OpenParams {
                 ring_pages: 20,
@@ -250,13 +295,15 @@ async fn relay_vpci_bus(
             },
             self.dma_client.as_ref(),
         )
-        .await?;
+        .await
+        .map_err(RelayError::OpenChannel)?;
 
         // FUTURE: handle more than one device. Note, though, that Hyper-V
         // doesn't really do this in practice.
         let (devices, _devices_recv) = mesh::channel();
-        let (vpci_client, devices) =
-            VpciClient::connect(self.driver_source.simple(), channel, mmio, devices).await?;
+        let (vpci_client, devices) = VpciClient::connect(driver, channel, mmio, devices)
+            .await
+            .map_err(RelayError::Connect)?;
 
         let Some(vpci_device) = devices.into_iter().next() else {
             tracing::info!(%instance_id, "no device on VPCI bus");
@@ -274,10 +321,7 @@ async fn relay_vpci_bus(
 
         tracing::info!(%instance_id, vendor_id = hw_ids.vendor_id, device_id = hw_ids.device_id, "vpci relay device arrived");
 
-        let (vpci_device, removed) = vpci_device
-            .init()
-            .await
-            .context("failed to initialize vpci device")?;
+        let (vpci_device, removed) = vpci_device.init().await.map_err(RelayError::InitDevice)?;
         let vpci_device = Arc::new(vpci_device);
 
         let device_name = format!("assigned_device:vpci-{instance_id}");
@@ -285,7 +329,8 @@ async fn relay_vpci_bus(
             .add_dyn_device(&self.driver_source, state_units, device_name, async |_| {
                 Ok(RelayedVpciDevice(vpci_device.clone()))
             })
-            .await?;
+            .await
+            .map_err(RelayError::AddDevice)?;
 
         let interrupt_mapper = VpciInterruptMapper::new(vpci_device);
 
@@ -310,7 +355,8 @@ async fn relay_vpci_bus(
                         anyhow::Ok(bus)
                     },
                 )
-                .await?
+                .await
+                .map_err(RelayError::AddDevice)?
         };
 
         entry.insert(RelayedDevice {
@@ -327,6 +373,31 @@ async fn relay_vpci_bus(
     }
 }
 
+/// Returns the GPA of the MMIO space for the bus in relay slot `index`.
+fn bus_mmio_gpa(mmio_range: MemoryRange, index: usize) -> Result<u64, RelayError> {
+    if (index as u64 + 1) * vpci_client::MMIO_SIZE > mmio_range.len() {
+        return Err(RelayError::OutOfMmioSpace);
+    }
+    Ok(mmio_range.start() + (index as u64) * vpci_client::MMIO_SIZE)
+}
+
+/// Returns the driver for relaying a VPCI bus, running on `target_vp` if
+/// specified.
+fn relay_driver(
+    driver_source: &VmTaskDriverSource,
+    target_vp: Option<u32>,
+    name: String,
+) -> VmTaskDriver {
+    match target_vp {
+        Some(vp) => driver_source
+            .builder()
+            .target_vp(vp)
+            .run_on_target(true)
+            .build(name),
+        None => driver_source.simple(),
+    }
+}
+
 #[derive(InspectMut)]
 #[inspect(transparent)]
 struct RelayedVpciDevice(Arc<VpciDevice>);
@@ -368,3 +439,148 @@ fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
         match state {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::RelayError;
+    use super::RelayedVpciDevice;
+    use super::bus_mmio_gpa;
+    use super::relay_driver;
+    use crate::test_helpers::FakeMemoryAccess;
+    use chipset_device::ChipsetDevice;
+    use chipset_device::io::IoResult;
+    use chipset_device::pci::PciConfigSpace;
+    use closeable_mutex::CloseableMutex;
+    use memory_range::MemoryRange;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use parking_lot::Mutex;
+    use pci_core::spec::cfg_space::Command;
+    use pci_core::spec::cfg_space::HeaderType00;
+    use std::sync::Arc;
+    use vmcore::vm_task::BuildVmTaskDriver;
+    use vmcore::vm_task::SingleDriver;
+    use vmcore::vm_task::SingleDriverBackend;
+    use vmcore::vm_task::VmTaskDriverSource;
+    use vpci::test_helpers::spawn_test_bus;
+
+    /// A PCI device with a single 4KB, 32-bit memory BAR.
+    #[derive(Default)]
+    struct BarDevice {
+        bar0: u32,
+    }
+
+    impl ChipsetDevice for BarDevice {
+        fn supports_pci(&mut self) -> Option<&mut dyn PciConfigSpace> {
+            Some(self)
+        }
+    }
+
+    impl PciConfigSpace for BarDevice {
+        fn pci_cfg_read(&mut self, offset: u16, value: &mut u32) -> IoResult {
+            *value = match HeaderType00(offset) {
+                HeaderType00::BAR0 => self.bar0 & 0xffff_f000,
+                _ => 0,
+            };
+            IoResult::Ok
+        }
+
+        fn pci_cfg_write(&mut self, offset: u16, value: u32) -> IoResult {
+            if HeaderType00(offset) == HeaderType00::BAR0 {
+                self.bar0 = value;
+            }
+            IoResult::Ok
+        }
+    }
+
+    /// A backend that records the target VP and `run_on_target` setting of
+    /// each driver it builds.
+    struct RecordingBackend {
+        inner: SingleDriverBackend<DefaultDriver>,
+        targets: Arc<Mutex<Vec<(Option<u32>, bool)>>>,
+    }
+
+    impl BuildVmTaskDriver for RecordingBackend {
+        type Driver = SingleDriver<DefaultDriver>;
+
+        fn build(&self, name: String, target_vp: Option<u32>, run_on_target: bool) -> Self::Driver {
+            self.targets.lock().push((target_vp, run_on_target));
+            self.inner.build(name, target_vp, run_on_target)
+        }
+    }
+
+    #[test]
+    fn test_bus_mmio_gpa() {
+        let range = MemoryRange::new(0x1000_0000..0x1000_0000 + 2 * vpci_client::MMIO_SIZE);
+        assert_eq!(bus_mmio_gpa(range, 0).unwrap(), 0x1000_0000);
+        assert_eq!(
+            bus_mmio_gpa(range, 1).unwrap(),
+            0x1000_0000 + vpci_client::MMIO_SIZE
+        );
+        assert!(matches!(
+            bus_mmio_gpa(range, 2),
+            Err(RelayError::OutOfMmioSpace)
+        ));
+    }
+
+    #[async_test]
+    async fn test_relay_driver_target_vp(driver: DefaultDriver) {
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let driver_source = VmTaskDriverSource::new(RecordingBackend {
+            inner: SingleDriverBackend::new(driver),
+            targets: targets.clone(),
+        });
+
+        let _ = relay_driver(&driver_source, Some(3), "relay".into());
+        let _ = relay_driver(&driver_source, None, "relay".into());
+        assert_eq!(targets.lock().as_slice(), &[(Some(3), true), (None, false)]);
+    }
+
+    #[async_test]
+    async fn test_cfg_command_enables_bars(driver: DefaultDriver) {
+        // Config space accesses go to the fake rather than the bus, but the
+        // bus must stay alive to serve the channel.
+        let (_bus, guest, _task) =
+            spawn_test_bus(&driver, Arc::new(CloseableMutex::new(BarDevice::default())));
+
+        let mmio = FakeMemoryAccess::new(0x1000_0000, vpci_client::MMIO_SIZE as usize);
+        let (_client, devices) = vpci_client::VpciClient::connect(
+            &driver,
+            guest,
+            Box::new(mmio.clone()),
+            mesh::channel().0,
+        )
+        .await
+        .unwrap();
+        let (device, _eject) = devices.into_iter().next().unwrap().init().await.unwrap();
+        let mut device = RelayedVpciDevice(Arc::new(device));
+
+        let bar0_offset = vpci_protocol::MMIO_PAGE_CONFIG_SPACE + HeaderType00::BAR0.0 as u64;
+        let command_offset =
+            vpci_protocol::MMIO_PAGE_CONFIG_SPACE + HeaderType00::STATUS_COMMAND.0 as u64;
+
+        // BAR writes are shadowed until MMIO is enabled.
+        device
+            .pci_cfg_write(HeaderType00::BAR0.0, 0x1234_5678)
+            .unwrap();
+        assert_eq!(mmio.read_offset(bar0_offset), 0);
+        let mut value = 0;
+        device
+            .pci_cfg_read(HeaderType00::BAR0.0, &mut value)
+            .unwrap();
+        assert_eq!(value, 0x1234_5000);
+
+        // Enabling MMIO flushes the BARs and writes the command register.
+        let command = u16::from(Command::new().with_mmio_enabled(true));
+        device
+            .pci_cfg_write(HeaderType00::STATUS_COMMAND.0, command.into())
+            .unwrap();
+        assert_eq!(mmio.read_offset(bar0_offset), 0x1234_5000);
+        assert_eq!(mmio.read_offset(command_offset), command.into());
+
+        device
+            .pci_cfg_read(HeaderType00::STATUS_COMMAND.0, &mut value)
+            .unwrap();
+        assert_eq!(value, command.into());
+    }
+}
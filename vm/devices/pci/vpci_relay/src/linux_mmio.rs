@@ -6,11 +6,13 @@
 use crate::CreateMemoryAccess;
 use anyhow::Context as _;
 use hcl::ioctl::MshvHvcall;
+use inspect::Inspect;
 use std::sync::Arc;
 use vpci_client::MemoryAccess;
 
 /// Accesses MMIO space directly via `/dev/mem`.
-pub struct DirectMmio(fs_err::File);
+#[derive(Inspect)]
+pub struct DirectMmio(#[inspect(skip)] fs_err::File);
 
 impl DirectMmio {
     /// Opens `/dev/mem` for MMIO access.
@@ -78,7 +80,8 @@ fn write(&mut self, addr: u64, value: u32) {
 }
 
 /// MMIO access via hypercalls.
-pub struct HypercallMmio(Arc<MshvHvcall>);
+#[derive(Inspect)]
+pub struct HypercallMmio(#[inspect(skip)] Arc<MshvHvcall>);
 
 impl HypercallMmio {
     /// Opens a hypercall interface for MMIO access.
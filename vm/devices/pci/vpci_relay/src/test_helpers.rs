@@ -0,0 +1,71 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Test helpers for the VPCI relay.
+
+use crate::CreateMemoryAccess;
+use inspect::Inspect;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use vpci_client::MemoryAccess;
+
+/// An in-memory [`MemoryAccess`] backed by a byte buffer mapped at a base GPA.
+///
+/// Accesses outside the buffer read as all ones and drop writes, like
+/// unbacked MMIO. Clones share the same buffer.
+#[derive(Clone, Inspect)]
+pub struct FakeMemoryAccess {
+    #[inspect(hex)]
+    base_gpa: u64,
+    #[inspect(skip)]
+    mem: Arc<Mutex<Vec<u8>>>,
+}
+
+impl FakeMemoryAccess {
+    /// Creates a zeroed buffer of `len` bytes mapped at `base_gpa`.
+    pub fn new(base_gpa: u64, len: usize) -> Self {
+        Self {
+            base_gpa,
+            mem: Arc::new(Mutex::new(vec![0; len])),
+        }
+    }
+
+    /// Reads the value at `offset` bytes into the buffer.
+    pub fn read_offset(&self, offset: u64) -> u32 {
+        self.clone().read(self.base_gpa + offset)
+    }
+
+    fn range(&self, addr: u64) -> Option<std::ops::Range<usize>> {
+        let start = usize::try_from(addr.checked_sub(self.base_gpa)?).ok()?;
+        let end = start.checked_add(4)?;
+        (end <= self.mem.lock().len()).then_some(start..end)
+    }
+}
+
+impl MemoryAccess for FakeMemoryAccess {
+    fn gpa(&mut self) -> u64 {
+        self.base_gpa
+    }
+
+    fn read(&mut self, addr: u64) -> u32 {
+        match self.range(addr) {
+            Some(range) => u32::from_le_bytes(self.mem.lock()[range].try_into().unwrap()),
+            None => !0,
+        }
+    }
+
+    fn write(&mut self, addr: u64, value: u32) {
+        if let Some(range) = self.range(addr) {
+            self.mem.lock()[range].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+impl CreateMemoryAccess for FakeMemoryAccess {
+    fn create_memory_access(&self, gpa: u64) -> anyhow::Result<Box<dyn MemoryAccess>> {
+        Ok(Box::new(Self {
+            base_gpa: gpa,
+            mem: self.mem.clone(),
+        }))
+    }
+}
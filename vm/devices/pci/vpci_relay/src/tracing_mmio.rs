@@ -0,0 +1,149 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! An MMIO access wrapper that traces VPCI bus traffic, for debugging device
+//! bring-up.
+
+use crate::CreateMemoryAccess;
+use inspect::Inspect;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use vpci_client::MemoryAccess;
+
+/// The number of recent accesses retained by [`TracingMmio`].
+const RECENT_ACCESS_COUNT: usize = 256;
+
+/// An MMIO access observed by [`TracingMmio`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MmioAccess {
+    /// A read of `value` from `addr`.
+    Read {
+        /// The address read.
+        addr: u64,
+        /// The value returned by the underlying access.
+        value: u32,
+    },
+    /// A write of `value` to `addr`.
+    Write {
+        /// The address written.
+        addr: u64,
+        /// The value written.
+        value: u32,
+    },
+}
+
+/// A [`CreateMemoryAccess`] wrapper that traces the reads and writes issued
+/// through the memory access instances it creates.
+///
+/// Each access is logged (rate limited) and the most recent accesses are kept
+/// in memory for retrieval via [`TracingMmio::recent_accesses`] or inspect.
+pub struct TracingMmio {
+    inner: Box<dyn CreateMemoryAccess>,
+    recent: Arc<Mutex<VecDeque<MmioAccess>>>,
+}
+
+impl TracingMmio {
+    /// Wraps `inner`, tracing all accesses made through it.
+    pub fn new(inner: Box<dyn CreateMemoryAccess>) -> Self {
+        Self {
+            inner,
+            recent: Default::default(),
+        }
+    }
+
+    /// Returns the most recent accesses, oldest first.
+    pub fn recent_accesses(&self) -> Vec<MmioAccess> {
+        self.recent.lock().iter().copied().collect()
+    }
+}
+
+impl Inspect for TracingMmio {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        let recent = self.recent.lock();
+        req.respond().field("inner", &self.inner).field(
+            "recent",
+            inspect::iter_by_index(recent.iter().map(|access| inspect::AsDebug(*access))),
+        );
+    }
+}
+
+impl CreateMemoryAccess for TracingMmio {
+    fn create_memory_access(&self, gpa: u64) -> anyhow::Result<Box<dyn MemoryAccess>> {
+        Ok(Box::new(TracingMmioInstance {
+            inner: self.inner.create_memory_access(gpa)?,
+            recent: self.recent.clone(),
+        }))
+    }
+}
+
+struct TracingMmioInstance {
+    inner: Box<dyn MemoryAccess>,
+    recent: Arc<Mutex<VecDeque<MmioAccess>>>,
+}
+
+impl TracingMmioInstance {
+    fn record(&self, access: MmioAccess) {
+        let mut recent = self.recent.lock();
+        if recent.len() == RECENT_ACCESS_COUNT {
+            recent.pop_front();
+        }
+        recent.push_back(access);
+    }
+}
+
+impl MemoryAccess for TracingMmioInstance {
+    fn gpa(&mut self) -> u64 {
+        self.inner.gpa()
+    }
+
+    fn read(&mut self, addr: u64) -> u32 {
+        let value = self.inner.read(addr);
+        tracelimit::info_ratelimited!(addr, value, "vpci mmio read");
+        self.record(MmioAccess::Read { addr, value });
+        value
+    }
+
+    fn write(&mut self, addr: u64, value: u32) {
+        tracelimit::info_ratelimited!(addr, value, "vpci mmio write");
+        self.inner.write(addr, value);
+        self.record(MmioAccess::Write { addr, value });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MmioAccess;
+    use super::TracingMmio;
+    use crate::CreateMemoryAccess;
+    use crate::test_helpers::FakeMemoryAccess;
+
+    #[test]
+    fn test_tracing_mmio_records_accesses() {
+        let mmio = TracingMmio::new(Box::new(FakeMemoryAccess::new(0, 0x10)));
+        let mut access = mmio.create_memory_access(0x1000).unwrap();
+
+        assert_eq!(access.gpa(), 0x1000);
+        access.write(0x1004, 5);
+        assert_eq!(access.read(0x1004), 5);
+        assert_eq!(access.read(0x1010), !0);
+
+        assert_eq!(
+            mmio.recent_accesses(),
+            [
+                MmioAccess::Write {
+                    addr: 0x1004,
+                    value: 5
+                },
+                MmioAccess::Read {
+                    addr: 0x1004,
+                    value: 5
+                },
+                MmioAccess::Read {
+                    addr: 0x1010,
+                    value: !0
+                },
+            ]
+        );
+    }
+}
@@ -388,6 +388,15 @@ pub trait VtlMemoryProtection {
     /// TODO: To remain generic may want to replace hvdef::HvMapGpaFlags with
     ///       something else.
     fn modify_vtl_page_setting(&self, pfn: u64, flags: hvdef::HvMapGpaFlags) -> anyhow::Result<()>;
+
+    /// Waits for previous calls to [`Self::modify_vtl_page_setting`] to take
+    /// effect for all processors.
+    ///
+    /// Implementations that apply changes synchronously need not override
+    /// this.
+    fn flush_vtl_page_settings(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 pub trait Processor: InspectMut {